@@ -1,6 +1,8 @@
-use super::ast::{get_addresing_mode, Ast};
+use super::ast::{get_addresing_mode, literal_u16, Ast};
 use super::instruction;
+use super::lexer::Span;
 use super::linker;
+use super::macros;
 use super::parser;
 
 pub struct Asm {
@@ -8,6 +10,7 @@ pub struct Asm {
     program: Vec<u8>,
     linker: linker::Linker,
     program_counter: u16,
+    origin: u16,
 }
 
 impl Asm {
@@ -16,46 +19,130 @@ impl Asm {
             parser: parser::Parser::new(text),
             linker: linker::Linker::new(origin),
             program_counter: origin,
+            origin,
             program: Vec::new(),
         }
     }
 
     pub fn compile(&mut self) -> Vec<u8> {
-        let ast = self.parser.parse();
-        self.linker.index(&ast);
-        self.visit(&ast);
-        self.program.clone()
+        match self.try_compile() {
+            Ok(program) => program,
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    pub fn try_compile(&mut self) -> Result<Vec<u8>, String> {
+        let ast = self
+            .parser
+            .try_parse()
+            .map_err(|err| format!("{err}"))?;
+        let ast = macros::expand_macros(ast)?;
+        self.linker.collect_constants(&ast)?;
+        self.linker.index(&ast)?;
+        self.visit(&ast)?;
+        Ok(self.program.clone())
     }
 
-    fn visit(&mut self, ast: &Ast) {
+    fn visit(&mut self, ast: &Ast) -> Result<(), String> {
         match ast {
             Ast::Statements(statements) => {
                 for statement in statements.iter() {
-                    self.visit(statement);
+                    self.visit(statement)?;
                 }
             }
             Ast::Instruction {
                 instruction: i,
                 args: a,
+                span,
             } => {
-                let instruction = instruction::get_instruction(i, get_addresing_mode(a));
-                let arg = self.get_args_bytes(a, instruction);
-                self.program.push(instruction.opcode);
-                if !arg.is_empty() {
-                    self.program.extend_from_slice(&arg);
-                }
-                self.program_counter += u16::from(instruction.len);
+                let mode = get_addresing_mode(a, *span).map_err(|err| format!("{err}"))?;
+                let instruction = instruction::get_instruction(i, mode);
+                let arg = self.get_args_bytes(a, instruction, *span)?;
+                let mut bytes = vec![instruction.opcode];
+                bytes.extend_from_slice(&arg);
+                self.emit_bytes(&bytes);
             }
             Ast::Label(_) => {}
-            _ => panic!("Asm :: unexpected node :{:?}", ast),
+            Ast::Directive { name, args, span } => self.emit_directive(name, args, *span)?,
+            Ast::ConstDef { .. } => {}
+            _ => return Err(format!("Asm :: unexpected node :{:?}", ast)),
+        }
+        Ok(())
+    }
+
+    /// Writes `bytes` at the offset `program_counter` maps to in `program`,
+    /// overwriting whatever is already there and growing the buffer if the
+    /// write extends past its current end. Every directive/instruction path
+    /// that emits output goes through here so a `.org` that seeks backward
+    /// (e.g. to patch a vector table after the main body) overwrites the
+    /// right bytes instead of silently appending at the wrong offset.
+    fn emit_bytes(&mut self, bytes: &[u8]) {
+        let offset = self.program_counter.wrapping_sub(self.origin) as usize;
+        let end = offset + bytes.len();
+        if end > self.program.len() {
+            self.program.resize(end, 0);
+        }
+        self.program[offset..end].copy_from_slice(bytes);
+        self.program_counter += bytes.len() as u16;
+    }
+
+    fn emit_directive(&mut self, name: &str, args: &[Ast], span: Span) -> Result<(), String> {
+        let constants = self.linker.constants();
+        match name {
+            "org" => {
+                let addr =
+                    literal_u16(&args[0], constants, span).map_err(|err| format!("{err}"))?;
+                let target_offset = addr.wrapping_sub(self.origin) as usize;
+                if target_offset > self.program.len() {
+                    self.program.resize(target_offset, 0);
+                }
+                self.program_counter = addr;
+            }
+            "byte" | "db" => {
+                let mut bytes = Vec::with_capacity(args.len());
+                for arg in args {
+                    let value =
+                        literal_u16(arg, constants, span).map_err(|err| format!("{err}"))?;
+                    if value > 0xFF {
+                        return Err(format!(
+                            "{}:{}: .byte : value {value:#06x} does not fit in a byte",
+                            span.start.line, span.start.col
+                        ));
+                    }
+                    bytes.push(value as u8);
+                }
+                self.emit_bytes(&bytes);
+            }
+            "word" | "dw" => {
+                let mut bytes = Vec::with_capacity(args.len() * 2);
+                for arg in args {
+                    let value =
+                        literal_u16(arg, constants, span).map_err(|err| format!("{err}"))?;
+                    bytes.extend_from_slice(&value.to_le_bytes());
+                }
+                self.emit_bytes(&bytes);
+            }
+            "res" => {
+                let count =
+                    literal_u16(&args[0], constants, span).map_err(|err| format!("{err}"))?;
+                self.emit_bytes(&vec![0u8; count as usize]);
+            }
+            other => {
+                return Err(format!(
+                    "{}:{}: Asm : unknown directive `.{other}`",
+                    span.start.line, span.start.col
+                ))
+            }
         }
+        Ok(())
     }
 
     fn get_args_bytes(
         &self,
         args: &Option<Box<Ast>>,
         instruction: &instruction::Instruction,
-    ) -> Vec<u8> {
+        span: Span,
+    ) -> Result<Vec<u8>, String> {
         let mut bytes = Vec::new();
         if let Some(args) = args {
             match args.as_ref() {
@@ -79,16 +166,71 @@ impl Asm {
                     bytes.push(*address8);
                 }
                 Ast::Label(label) => {
-                    bytes.extend_from_slice(&self.linker.link(
-                        label,
-                        instruction,
-                        self.program_counter,
-                    ));
+                    bytes.extend_from_slice(
+                        &self
+                            .linker
+                            .link(label, instruction, self.program_counter)
+                            .map_err(|err| {
+                                format!("{}:{}: {err}", span.start.line, span.start.col)
+                            })?,
+                    );
                 }
                 Ast::Number8(number) => bytes.push(*number),
-                _ => panic!("Asm : unexpected node : {:?}", *args),
+                Ast::Immediate(inner) => {
+                    let value = literal_u16(inner, self.linker.constants(), span)
+                        .map_err(|err| format!("{err}"))?;
+                    if value > 0xFF {
+                        return Err(format!(
+                            "{}:{}: immediate operand {value:#06x} does not fit in a byte",
+                            span.start.line, span.start.col
+                        ));
+                    }
+                    bytes.push(value as u8);
+                }
+                _ => {
+                    return Err(format!(
+                        "{}:{}: Asm : unexpected node : {:?}",
+                        span.start.line, span.start.col, *args
+                    ))
+                }
             }
         }
-        bytes
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn macro_param_used_as_immediate_operand_compiles_to_the_call_arg_byte() {
+        let src = String::from(
+            "        .macro loadimm value\n                LDA #value\n        .endmacro\n        loadimm $20\n",
+        );
+        let program = Asm::new(src, 0x0600).try_compile().unwrap();
+        assert_eq!(program.len(), 2);
+        assert_eq!(program[1], 0x20);
+    }
+
+    #[test]
+    fn byte_word_and_res_directives_emit_the_expected_bytes() {
+        let src = String::from(".byte $01,$02\n.word $1234\n.res 2\n");
+        let program = Asm::new(src, 0x0600).try_compile().unwrap();
+        assert_eq!(program, vec![0x01, 0x02, 0x34, 0x12, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn org_seeking_forward_leaves_the_gap_zero_filled() {
+        let src = String::from(".org $0600\n.byte $AA\n.org $0604\n.byte $BB\n");
+        let program = Asm::new(src, 0x0600).try_compile().unwrap();
+        assert_eq!(program, vec![0xAA, 0x00, 0x00, 0x00, 0xBB]);
+    }
+
+    #[test]
+    fn org_seeking_backward_overwrites_bytes_already_emitted_in_place() {
+        let src = String::from(".org $0600\nLDA #$01\n.org $0600\n.byte $FF\n");
+        let program = Asm::new(src, 0x0600).try_compile().unwrap();
+        assert_eq!(program, vec![0xFF, 0x01]);
     }
 }
@@ -1,13 +1,16 @@
 use super::instruction::Mode;
+use super::lexer::Span;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Ast {
     Statements(Vec<Ast>),
     Instruction {
         instruction: String,
         args: Option<Box<Ast>>,
+        span: Span,
     },
     Label(String),
+    Accumulator,
     Number8(u8),
     Absolute(u16),
     ZeroPage(u8),
@@ -16,31 +19,116 @@ pub enum Ast {
     ZeroPageIndexed(u8, char),
     IndirectX(u8),
     IndirectY(u8),
+    /// Wraps an operand that was written after a `#` but isn't known to be a
+    /// literal yet at parse time — specifically a macro parameter used as
+    /// an immediate operand (`LDA #value`). `value` is resolved once the
+    /// macro call's argument is substituted in for it, which may land any
+    /// other operand `Ast` inside (`Number8`, `ZeroPage`, a constant
+    /// `Label`, ...); codegen then reads it back out as a single byte
+    /// instead of trusting the substituted node's own addressing mode.
+    Immediate(Box<Ast>),
+    MacroDef {
+        name: String,
+        params: Vec<String>,
+        body: Vec<Ast>,
+    },
+    MacroCall {
+        name: String,
+        args: Vec<Ast>,
+    },
+    Directive {
+        name: String,
+        args: Vec<Ast>,
+        span: Span,
+    },
+    ConstDef {
+        name: String,
+        value: Box<Ast>,
+        span: Span,
+    },
+}
+
+/// An error raised while turning a parsed `Ast` into bytes (addressing-mode
+/// resolution, directive/constant evaluation), carrying the span of the
+/// statement it came from so callers can print `line:col` plus the
+/// offending source slice, the same way `LexError`/`ParseError` do.
+#[derive(Debug)]
+pub struct CodegenError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl std::fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.span.start.line, self.span.start.col, self.message
+        )
+    }
+}
+
+/// Resolves a `Directive`/`ConstDef` argument down to a concrete 16-bit
+/// value: numeric literals resolve to themselves, and a bare identifier
+/// resolves through `constants` so a constant can stand in anywhere a
+/// directive argument is expected. This does not cover a constant used as
+/// a normal instruction operand (e.g. `LDA SCREEN`) — that still goes
+/// through `get_addresing_mode` below, which isn't constant-aware. `span`
+/// is the statement the value came from, so a failure can be reported as
+/// `line:col: ...`.
+pub fn literal_u16(
+    ast: &Ast,
+    constants: &std::collections::HashMap<String, u16>,
+    span: Span,
+) -> Result<u16, CodegenError> {
+    match ast {
+        Ast::Number8(n) => Ok(u16::from(*n)),
+        Ast::ZeroPage(n) => Ok(u16::from(*n)),
+        Ast::Absolute(n) => Ok(*n),
+        Ast::Label(name) => constants.get(name).copied().ok_or_else(|| CodegenError {
+            message: format!("literal_u16 : unknown constant `{name}`"),
+            span,
+        }),
+        other => Err(CodegenError {
+            message: format!("literal_u16 : expected a literal value, got {:?}", other),
+            span,
+        }),
+    }
 }
 
-pub fn get_addresing_mode(ast: &Option<Box<Ast>>) -> Mode {
+pub fn get_addresing_mode(ast: &Option<Box<Ast>>, span: Span) -> Result<Mode, CodegenError> {
     if let Some(ast) = ast {
         match ast.as_ref() {
-            Ast::Absolute(_) => Mode::Absolute,
-            Ast::AbsoluteIndirect(_) => Mode::Indirect,
-            Ast::IndirectX(_) => Mode::IndirectX,
-            Ast::IndirectY(_) => Mode::IndirectY,
-            Ast::ZeroPage(_) => Mode::ZeroPage,
+            Ast::Absolute(_) => Ok(Mode::Absolute),
+            Ast::AbsoluteIndirect(_) => Ok(Mode::Indirect),
+            Ast::IndirectX(_) => Ok(Mode::IndirectX),
+            Ast::IndirectY(_) => Ok(Mode::IndirectY),
+            Ast::ZeroPage(_) => Ok(Mode::ZeroPage),
             Ast::AsoluteIndexed(_, reg) => match reg {
-                'x' | 'X' => Mode::AbsoluteX,
-                'y' | 'Y' => Mode::AbsoluteY,
-                _ => panic!("get_addressing_mode : Unknown register {reg}"),
+                'x' | 'X' => Ok(Mode::AbsoluteX),
+                'y' | 'Y' => Ok(Mode::AbsoluteY),
+                _ => Err(CodegenError {
+                    message: format!("get_addressing_mode : Unknown register {reg}"),
+                    span,
+                }),
             },
             Ast::ZeroPageIndexed(_, reg) => match reg {
-                'x' | 'X' => Mode::ZeroPageX,
-                'y' | 'Y' => Mode::ZeroPageY,
-                _ => panic!("get_addressing_mode : Unknown register {reg}"),
+                'x' | 'X' => Ok(Mode::ZeroPageX),
+                'y' | 'Y' => Ok(Mode::ZeroPageY),
+                _ => Err(CodegenError {
+                    message: format!("get_addressing_mode : Unknown register {reg}"),
+                    span,
+                }),
             },
-            Ast::Label(_) => Mode::Relative,
-            Ast::Number8(_) => Mode::Immediate,
-            _ => panic!("get_addressing_mode : Unexpected node : {:?}", ast),
+            Ast::Label(_) => Ok(Mode::Relative),
+            Ast::Number8(_) => Ok(Mode::Immediate),
+            Ast::Immediate(_) => Ok(Mode::Immediate),
+            _ => Err(CodegenError {
+                message: format!("get_addressing_mode : Unexpected node : {:?}", ast),
+                span,
+            }),
         }
     } else {
-        Mode::Implicit
+        Ok(Mode::Implicit)
     }
 }
@@ -43,10 +43,85 @@ pub fn get_token_type(token: &Token) -> TokenType {
     }
 }
 
+/// A single point in the source text, 1-indexed so it can be printed
+/// directly as `line:col`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Self { line: 1, col: 1 }
+    }
+}
+
+/// The region of source text a token (or a group of tokens) was read from.
+///
+/// `start_offset`/`end_offset` are char indices into the lexer's input and
+/// let callers recover the offending slice without re-lexing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+    pub start_offset: usize,
+    pub end_offset: usize,
+}
+
+impl Span {
+    /// Unions two spans, taking the earliest start and the latest end.
+    /// Used to report the whole region covered by a multi-token construct.
+    pub fn merge(&self, other: &Span) -> Span {
+        let (start, start_offset) = if self.start_offset <= other.start_offset {
+            (self.start, self.start_offset)
+        } else {
+            (other.start, other.start_offset)
+        };
+        let (end, end_offset) = if self.end_offset >= other.end_offset {
+            (self.end, self.end_offset)
+        } else {
+            (other.end, other.end_offset)
+        };
+        Span {
+            start,
+            end,
+            start_offset,
+            end_offset,
+        }
+    }
+}
+
+/// A token together with the span it was read from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+/// An error raised while lexing, carrying the span of the offending text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl std::fmt::Display for LexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.span.start.line, self.span.start.col, self.message
+        )
+    }
+}
+
 #[derive(Debug)]
 pub struct Lexer {
     text: Vec<char>,
     position: usize,
+    line: usize,
+    col: usize,
 }
 
 impl Lexer {
@@ -54,18 +129,31 @@ impl Lexer {
         Self {
             text: t.chars().collect::<Vec<char>>(),
             position: 0,
+            line: 1,
+            col: 1,
         }
     }
 
-    pub fn get_next_token(&mut self) -> Result<Token, String> {
+    /// Returns the source slice a span was read from, for error reporting.
+    pub fn slice(&self, span: &Span) -> String {
+        self.text[span.start_offset..span.end_offset]
+            .iter()
+            .collect()
+    }
+
+    pub fn get_next_token(&mut self) -> Result<Spanned<Token>, LexError> {
         if self.position >= self.text.len() {
-            return Ok(Token::Eof);
+            return Ok(self.eof_token());
         }
         while (self.text[self.position] == ' ') | (self.text[self.position] == '\t') {
-            self.position += 1;
+            self.advance();
+        }
+        if self.position >= self.text.len() {
+            return Ok(self.eof_token());
         }
-        let current_position = self.position;
-        let current_char = self.text[current_position];
+        let start = self.current_position();
+        let start_offset = self.position;
+        let current_char = self.text[self.position];
         let token = match current_char {
             '(' => Ok(Token::Symbol(Symbol::LPar)),
             ')' => Ok(Token::Symbol(Symbol::RPar)),
@@ -83,17 +171,81 @@ impl Lexer {
             '%' => self.parse_binary(),
             unknown => Err(format!("Unknown char : {unknown}")),
         };
+        self.advance();
+        let end = self.current_position();
+        let end_offset = self.position;
+        let span = Span {
+            start,
+            end,
+            start_offset,
+            end_offset,
+        };
+        match token {
+            Ok(node) => Ok(Spanned { node, span }),
+            Err(message) => Err(LexError { message, span }),
+        }
+    }
+
+    fn eof_token(&self) -> Spanned<Token> {
+        let pos = self.current_position();
+        let span = Span {
+            start: pos,
+            end: pos,
+            start_offset: self.position,
+            end_offset: self.position,
+        };
+        Spanned {
+            node: Token::Eof,
+            span,
+        }
+    }
+
+    fn current_position(&self) -> Position {
+        Position {
+            line: self.line,
+            col: self.col,
+        }
+    }
+
+    /// Consumes the character under the cursor, advancing `line`/`col`
+    /// the way a reader would: a newline starts a new line, anything
+    /// else moves one column to the right.
+    fn advance(&mut self) {
+        if self.position < self.text.len() {
+            if self.text[self.position] == '\n' {
+                self.line += 1;
+                self.col = 1;
+            } else {
+                self.col += 1;
+            }
+        }
         self.position += 1;
-        token
     }
 
     fn skip_comment(&mut self) {
-        self.position += 1;
+        self.advance();
         self.parse_if(|c: char| c != '\n');
     }
 
     fn parse_text(&mut self) -> Result<Token, String> {
-        let text = self.parse_if(|c: char| c.is_alphabetic());
+        // '.' (directives) and '=' (constant assignment) are not
+        // alphabetic, so they would otherwise be swallowed without being
+        // captured in the resulting text: fold the leading character in
+        // by hand, then let the usual run of identifier characters follow it.
+        let leading = self.text[self.position];
+        if (leading == '.') | (leading == '=') {
+            let has_more = self.position < self.text.len() - 1;
+            if has_more && self.text[self.position + 1].is_alphabetic() {
+                self.advance();
+                let rest = self.parse_if(|c: char| c.is_alphanumeric() || c == '_');
+                return Ok(Token::Text(format!("{leading}{rest}")));
+            }
+            return Ok(Token::Text(leading.to_string()));
+        }
+        // An identifier starts with an alphabetic character, but once
+        // started it may continue with digits or underscores, so labels
+        // like `loop1` or `draw_sprite` are captured as a single token.
+        let text = self.parse_if(|c: char| c.is_alphanumeric() || c == '_');
         Ok(Token::Text(text))
     }
 
@@ -107,7 +259,7 @@ impl Lexer {
     }
 
     fn parse_hexa(&mut self) -> Result<Token, String> {
-        self.position += 1;
+        self.advance();
         let hexa = self.parse_if(|c: char| matches!(c, '0'..='9' | 'a'..='f' | 'A'..='F'));
         match hexa.len() {
             1..=2 => Ok(Token::Hexa8(u8::from_str_radix(&hexa, 16).unwrap())),
@@ -117,7 +269,7 @@ impl Lexer {
     }
 
     fn parse_binary(&mut self) -> Result<Token, String> {
-        self.position += 1;
+        self.advance();
         let bin = self.parse_if(|c: char| (c == '0') | (c == '1'));
         match bin.len() {
             1..=8 => Ok(Token::Binary(u8::from_str_radix(&bin, 2).unwrap())),
@@ -136,11 +288,12 @@ impl Lexer {
             if self.position == self.text.len() - 1 {
                 break;
             }
-            self.position += 1;
+            self.advance();
             current_char = self.text[self.position];
         }
         if self.position < self.text.len() - 1 {
             self.position -= 1;
+            self.col -= 1;
         }
         res
     }
@@ -155,30 +308,63 @@ mod tests {
         let str = String::from("myLabel");
         let mut lexer = Lexer::new(str);
         assert_eq!(
-            lexer.get_next_token(),
-            Ok(Token::Text(String::from("myLabel")))
+            lexer.get_next_token().unwrap().node,
+            Token::Text(String::from("myLabel"))
         )
     }
 
+    #[test]
+    fn read_text_with_digits() {
+        let str = String::from("loop1");
+        let mut lexer = Lexer::new(str);
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Text(String::from("loop1"))
+        )
+    }
+
+    #[test]
+    fn read_text_with_underscore() {
+        let str = String::from("draw_sprite");
+        let mut lexer = Lexer::new(str);
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Text(String::from("draw_sprite"))
+        )
+    }
+
+    #[test]
+    fn read_label_then_numeric_operand() {
+        // `line_2` is one identifier token; the space ends it, so the
+        // following `$10` is a separate Hexa8 token, not swallowed into it.
+        let str = String::from("line_2 $10");
+        let mut lexer = Lexer::new(str);
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Text(String::from("line_2"))
+        );
+        assert_eq!(lexer.get_next_token().unwrap().node, Token::Hexa8(0x10));
+    }
+
     #[test]
     fn read_decimal() {
         let str = String::from("123");
         let mut lexer = Lexer::new(str);
-        assert_eq!(lexer.get_next_token(), Ok(Token::Decimal(123)))
+        assert_eq!(lexer.get_next_token().unwrap().node, Token::Decimal(123))
     }
 
     #[test]
     fn read_hexa8() {
         let str = String::from("$A0");
         let mut lexer = Lexer::new(str);
-        assert_eq!(lexer.get_next_token(), Ok(Token::Hexa8(0xA0)))
+        assert_eq!(lexer.get_next_token().unwrap().node, Token::Hexa8(0xA0))
     }
 
     #[test]
     fn read_hexa16() {
         let str = String::from("$0BF1");
         let mut lexer = Lexer::new(str);
-        assert_eq!(lexer.get_next_token(), Ok(Token::Hexa16(0x0BF1)))
+        assert_eq!(lexer.get_next_token().unwrap().node, Token::Hexa16(0x0BF1))
     }
 
     #[test]
@@ -192,25 +378,40 @@ mod tests {
     fn read_bin() {
         let str = String::from("%101101");
         let mut lexer = Lexer::new(str);
-        assert_eq!(lexer.get_next_token(), Ok(Token::Binary(0b101101)))
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Binary(0b101101)
+        )
     }
 
     #[test]
     fn skip_comment_single() {
         let str = String::from(";a comment");
         let mut lexer = Lexer::new(str);
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::SemiColon)));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Eof));
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::SemiColon)
+        );
+        assert_eq!(lexer.get_next_token().unwrap().node, Token::Eof);
     }
 
     #[test]
     fn skip_comment_multiple() {
         let str = String::from(";a comment\n; other comment");
         let mut lexer = Lexer::new(str);
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::SemiColon)));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::NewLine)));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::SemiColon)));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Eof));
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::SemiColon)
+        );
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::NewLine)
+        );
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::SemiColon)
+        );
+        assert_eq!(lexer.get_next_token().unwrap().node, Token::Eof);
     }
 
     #[test]
@@ -221,33 +422,42 @@ mod tests {
         );
         let mut lexer = Lexer::new(str);
         assert_eq!(
-            lexer.get_next_token(),
-            Ok(Token::Text(String::from("myLabel")))
+            lexer.get_next_token().unwrap().node,
+            Token::Text(String::from("myLabel"))
+        );
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::NewLine)
         );
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::NewLine)));
         assert_eq!(
-            lexer.get_next_token(),
-            Ok(Token::Text(String::from("otherLine")))
+            lexer.get_next_token().unwrap().node,
+            Token::Text(String::from("otherLine"))
         );
-        assert_eq!(lexer.get_next_token(), Ok(Token::Eof));
+        assert_eq!(lexer.get_next_token().unwrap().node, Token::Eof);
     }
 
     #[test]
     fn read_complete_instruction() {
         let str = String::from("JMP ($AABB) ; this is a comment");
         let mut lexer = Lexer::new(str);
-        println!("0");
-        assert_eq!(lexer.get_next_token(), Ok(Token::Text(String::from("JMP"))));
-        println!("1");
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::LPar)));
-        println!("2");
-        assert_eq!(lexer.get_next_token(), Ok(Token::Hexa16(0xAABB)));
-        println!("3");
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::RPar)));
-        println!("4");
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::SemiColon)));
-        println!("5");
-        assert_eq!(lexer.get_next_token(), Ok(Token::Eof));
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Text(String::from("JMP"))
+        );
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::LPar)
+        );
+        assert_eq!(lexer.get_next_token().unwrap().node, Token::Hexa16(0xAABB));
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::RPar)
+        );
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::SemiColon)
+        );
+        assert_eq!(lexer.get_next_token().unwrap().node, Token::Eof);
     }
 
     #[test]
@@ -258,23 +468,59 @@ mod tests {
         );
         let mut lexer = Lexer::new(str);
         assert_eq!(
-            lexer.get_next_token(),
-            Ok(Token::Text(String::from("myLabel")))
+            lexer.get_next_token().unwrap().node,
+            Token::Text(String::from("myLabel"))
+        );
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::Colon)
+        );
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Text(String::from("JMP"))
+        );
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::LPar)
+        );
+        assert_eq!(lexer.get_next_token().unwrap().node, Token::Hexa16(0xAABB));
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::RPar)
+        );
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::SemiColon)
+        );
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::NewLine)
+        );
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Text(String::from("ADC"))
+        );
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::LPar)
+        );
+        assert_eq!(lexer.get_next_token().unwrap().node, Token::Hexa8(0xFF));
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::Coma)
+        );
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Text(String::from("X"))
+        );
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::RPar)
+        );
+        assert_eq!(
+            lexer.get_next_token().unwrap().node,
+            Token::Symbol(Symbol::SemiColon)
         );
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::Colon)));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Text(String::from("JMP"))));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::LPar)));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Hexa16(0xAABB)));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::RPar)));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::SemiColon)));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::NewLine)));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Text(String::from("ADC"))));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::LPar)));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Hexa8(0xFF)));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::Coma)));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Text(String::from("X"))));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::RPar)));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Symbol(Symbol::SemiColon)));
-        assert_eq!(lexer.get_next_token(), Ok(Token::Eof));
+        assert_eq!(lexer.get_next_token().unwrap().node, Token::Eof);
     }
 }
@@ -1,8 +1,9 @@
-use super::ast::{get_addresing_mode, Ast};
+use super::ast::{get_addresing_mode, literal_u16, Ast};
 use super::instruction;
 
 pub struct Linker {
     labels_indexes: std::collections::HashMap<String, u16>,
+    constants: std::collections::HashMap<String, u16>,
     program_counter: u16,
 }
 
@@ -10,15 +11,40 @@ impl Linker {
     pub fn new(origin: u16) -> Self {
         Self {
             labels_indexes: std::collections::HashMap::new(),
+            constants: std::collections::HashMap::new(),
             program_counter: origin,
         }
     }
 
-    pub fn index(&mut self, ast: &Ast) {
+    /// Records every `name = value` constant up front, the same way
+    /// `index` pre-computes label addresses, so a constant can be
+    /// referenced anywhere in the source regardless of where it's defined.
+    pub fn collect_constants(&mut self, ast: &Ast) -> Result<(), String> {
         match ast {
             Ast::Statements(statements) => {
                 for statement in statements.iter() {
-                    self.index(statement);
+                    self.collect_constants(statement)?;
+                }
+            }
+            Ast::ConstDef { name, value, span } => {
+                let value = literal_u16(value, &self.constants, *span)
+                    .map_err(|err| format!("{err}"))?;
+                self.constants.insert(name.clone(), value);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn constants(&self) -> &std::collections::HashMap<String, u16> {
+        &self.constants
+    }
+
+    pub fn index(&mut self, ast: &Ast) -> Result<(), String> {
+        match ast {
+            Ast::Statements(statements) => {
+                for statement in statements.iter() {
+                    self.index(statement)?;
                 }
             }
             Ast::Label(label) => {
@@ -28,16 +54,42 @@ impl Linker {
             Ast::Instruction {
                 instruction: i,
                 args: a,
+                span,
             } => {
-                let instruction = instruction::get_instruction(i, get_addresing_mode(a));
+                let mode = get_addresing_mode(a, *span).map_err(|err| format!("{err}"))?;
+                let instruction = instruction::get_instruction(i, mode);
                 self.program_counter += u16::from(instruction.len);
             }
+            Ast::Directive { name, args, span } => match name.as_str() {
+                "org" => {
+                    self.program_counter = literal_u16(&args[0], &self.constants, *span)
+                        .map_err(|err| format!("{err}"))?
+                }
+                "byte" | "db" => self.program_counter += args.len() as u16,
+                "word" | "dw" => self.program_counter += args.len() as u16 * 2,
+                "res" => {
+                    self.program_counter += literal_u16(&args[0], &self.constants, *span)
+                        .map_err(|err| format!("{err}"))?
+                }
+                other => {
+                    return Err(format!(
+                        "{}:{}: Linker : unknown directive `.{other}`",
+                        span.start.line, span.start.col
+                    ))
+                }
+            },
+            Ast::ConstDef { .. } => {}
             _ => {}
         }
+        Ok(())
     }
 
-    fn get(&self, label: &str) -> u16 {
-        *self.labels_indexes.get(label).unwrap()
+    fn get(&self, label: &str) -> Result<u16, String> {
+        self.labels_indexes
+            .get(label)
+            .or_else(|| self.constants.get(label))
+            .copied()
+            .ok_or_else(|| format!("Linker : unknown label {label}"))
     }
 
     pub fn link(
@@ -45,9 +97,9 @@ impl Linker {
         label: &str,
         instruction: &instruction::Instruction,
         asm_program_counter: u16,
-    ) -> Vec<u8> {
+    ) -> Result<Vec<u8>, String> {
         let mut bytes = Vec::new();
-        let label_index = self.get(label);
+        let label_index = self.get(label)?;
         match instruction.mode {
             instruction::Mode::Relative => {
                 let mut offset: u8 =
@@ -59,12 +111,12 @@ impl Linker {
             }
             instruction::Mode::Absolute => bytes.extend_from_slice(&label_index.to_le_bytes()),
             _ => {
-                panic!(
+                return Err(format!(
                     "Asm : calculate_offset Unexpected mode: {:?}",
                     instruction.mode
-                )
+                ))
             }
         }
-        bytes
+        Ok(bytes)
     }
 }
@@ -0,0 +1,336 @@
+use super::ast::Ast;
+#[cfg(test)]
+use super::lexer::Span;
+use std::collections::HashMap;
+
+/// Hard cap on how many passes the expansion loop will take before giving
+/// up, so a macro that (directly or indirectly) calls itself without ever
+/// bottoming out fails with an error instead of hanging.
+const MAX_EXPANSION_PASSES: usize = 64;
+
+struct MacroDef {
+    params: Vec<String>,
+    body: Vec<Ast>,
+}
+
+/// Expands every `Ast::MacroCall` in `ast` inline, using the `Ast::MacroDef`
+/// blocks collected from the same statement list, and strips the
+/// definitions out of the result. Runs once, before `Linker::index`/
+/// `Asm::visit`, so the rest of the pipeline never sees macro nodes.
+pub fn expand_macros(ast: Ast) -> Result<Ast, String> {
+    let statements = match ast {
+        Ast::Statements(statements) => statements,
+        other => return Ok(other),
+    };
+    let defs = collect_defs(&statements)?;
+    let mut statements = statements;
+    let mut expansion = 0usize;
+    for _ in 0..MAX_EXPANSION_PASSES {
+        let (next, expanded_any) = expand_pass(statements, &defs, &mut expansion)?;
+        statements = next;
+        if !expanded_any {
+            let statements = statements
+                .into_iter()
+                .filter(|statement| !matches!(statement, Ast::MacroDef { .. }))
+                .collect();
+            return Ok(Ast::Statements(statements));
+        }
+    }
+    Err(String::from(
+        "Macro expansion exceeded recursion depth limit, check for a self-referencing macro",
+    ))
+}
+
+fn collect_defs(statements: &[Ast]) -> Result<HashMap<String, MacroDef>, String> {
+    let mut defs = HashMap::new();
+    for statement in statements {
+        if let Ast::MacroDef { name, params, body } = statement {
+            defs.insert(
+                name.clone(),
+                MacroDef {
+                    params: params.clone(),
+                    body: body.clone(),
+                },
+            );
+        }
+    }
+    Ok(defs)
+}
+
+/// Names that `body` itself declares as labels (`Ast::Label` appearing as a
+/// statement, not as an operand reference). Only these need suffixing on
+/// expansion: anything else is a reference to a label/constant defined
+/// outside the macro and must resolve against the global symbol table
+/// exactly as written.
+fn local_labels(body: &[Ast]) -> std::collections::HashSet<String> {
+    body.iter()
+        .filter_map(|statement| match statement {
+            Ast::Label(name) => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Expands every `MacroCall` found at the top of `statements` by one level,
+/// returning the rewritten statement list and whether anything was expanded.
+/// Nested calls surface back out as ordinary `MacroCall` nodes and are
+/// picked up by the next pass.
+fn expand_pass(
+    statements: Vec<Ast>,
+    defs: &HashMap<String, MacroDef>,
+    expansion: &mut usize,
+) -> Result<(Vec<Ast>, bool), String> {
+    let mut result = Vec::with_capacity(statements.len());
+    let mut expanded_any = false;
+    for statement in statements {
+        match statement {
+            Ast::MacroCall { name, args } => {
+                let def = defs
+                    .get(&name)
+                    .ok_or_else(|| format!("Macro expansion : unknown macro `{name}`"))?;
+                if def.params.len() != args.len() {
+                    return Err(format!(
+                        "Macro expansion : `{name}` expects {} argument(s), got {}",
+                        def.params.len(),
+                        args.len()
+                    ));
+                }
+                *expansion += 1;
+                let suffix = format!("__macro{expansion}");
+                let locals = local_labels(&def.body);
+                for node in &def.body {
+                    result.push(substitute(node, &def.params, &args, &suffix, &locals));
+                }
+                expanded_any = true;
+            }
+            other => result.push(other),
+        }
+    }
+    Ok((result, expanded_any))
+}
+
+/// Rewrites `ast`, replacing any identifier that names a macro parameter
+/// with the matching call argument, and disambiguating every label the
+/// macro body itself declares (`locals`) with `suffix` so two expansions
+/// of the same macro can't collide. Every other identifier — a reference
+/// to a label or constant defined outside the macro — is left untouched.
+fn substitute(
+    ast: &Ast,
+    params: &[String],
+    args: &[Ast],
+    suffix: &str,
+    locals: &std::collections::HashSet<String>,
+) -> Ast {
+    match ast {
+        Ast::Statements(statements) => Ast::Statements(
+            statements
+                .iter()
+                .map(|s| substitute(s, params, args, suffix, locals))
+                .collect(),
+        ),
+        Ast::Instruction {
+            instruction,
+            args: a,
+            span,
+        } => Ast::Instruction {
+            instruction: instruction.clone(),
+            args: a
+                .as_ref()
+                .map(|a| Box::new(substitute(a, params, args, suffix, locals))),
+            span: *span,
+        },
+        Ast::Immediate(inner) => {
+            Ast::Immediate(Box::new(substitute(inner, params, args, suffix, locals)))
+        }
+        Ast::Label(name) => match params.iter().position(|p| p == name) {
+            Some(index) => args[index].clone(),
+            None if locals.contains(name) => Ast::Label(format!("{name}{suffix}")),
+            None => Ast::Label(name.clone()),
+        },
+        Ast::MacroCall { name, args: call_args } => Ast::MacroCall {
+            name: name.clone(),
+            args: call_args
+                .iter()
+                .map(|a| substitute(a, params, args, suffix, locals))
+                .collect(),
+        },
+        Ast::Directive {
+            name,
+            args: directive_args,
+            span,
+        } => Ast::Directive {
+            name: name.clone(),
+            args: directive_args
+                .iter()
+                .map(|a| substitute(a, params, args, suffix, locals))
+                .collect(),
+            span: *span,
+        },
+        Ast::ConstDef { name, value, span } => Ast::ConstDef {
+            name: name.clone(),
+            value: Box::new(substitute(value, params, args, suffix, locals)),
+            span: *span,
+        },
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn def(params: &[&str], body: Vec<Ast>) -> Ast {
+        Ast::MacroDef {
+            name: String::from("m"),
+            params: params.iter().map(|p| p.to_string()).collect(),
+            body,
+        }
+    }
+
+    #[test]
+    fn expands_call_with_substituted_args() {
+        let ast = Ast::Statements(vec![
+            def(
+                &["addr"],
+                vec![Ast::Instruction {
+                    instruction: String::from("LDA"),
+                    args: Some(Box::new(Ast::Label(String::from("addr")))),
+                    span: Span::default(),
+                }],
+            ),
+            Ast::MacroCall {
+                name: String::from("m"),
+                args: vec![Ast::ZeroPage(0x10)],
+            },
+        ]);
+        let expanded = expand_macros(ast).unwrap();
+        match expanded {
+            Ast::Statements(statements) => {
+                assert_eq!(statements.len(), 1);
+                match &statements[0] {
+                    Ast::Instruction { instruction, args, .. } => {
+                        assert_eq!(instruction, "LDA");
+                        assert!(matches!(args.as_deref(), Some(Ast::ZeroPage(0x10))));
+                    }
+                    other => panic!("unexpected node : {:?}", other),
+                }
+            }
+            other => panic!("unexpected node : {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reference_to_a_global_label_is_left_untouched() {
+        // `print_string` is not a macro parameter and is never defined as a
+        // label inside the macro body, so it must resolve against whatever
+        // defines it elsewhere in the file, unsuffixed.
+        let body = vec![Ast::Instruction {
+            instruction: String::from("JSR"),
+            args: Some(Box::new(Ast::Label(String::from("print_string")))),
+            span: Span::default(),
+        }];
+        let ast = Ast::Statements(vec![
+            def(&[], body),
+            Ast::MacroCall {
+                name: String::from("m"),
+                args: vec![],
+            },
+        ]);
+        let expanded = expand_macros(ast).unwrap();
+        match expanded {
+            Ast::Statements(statements) => {
+                assert_eq!(statements.len(), 1);
+                match &statements[0] {
+                    Ast::Instruction { args, .. } => {
+                        assert!(matches!(
+                            args.as_deref(),
+                            Some(Ast::Label(name)) if name == "print_string"
+                        ));
+                    }
+                    other => panic!("unexpected node : {:?}", other),
+                }
+            }
+            other => panic!("unexpected node : {:?}", other),
+        }
+    }
+
+    #[test]
+    fn param_is_substituted_inside_a_directive_argument() {
+        let body = vec![Ast::Directive {
+            name: String::from("byte"),
+            args: vec![Ast::Label(String::from("val"))],
+            span: Span::default(),
+        }];
+        let ast = Ast::Statements(vec![
+            def(&["val"], body),
+            Ast::MacroCall {
+                name: String::from("m"),
+                args: vec![Ast::Number8(0x05)],
+            },
+        ]);
+        let expanded = expand_macros(ast).unwrap();
+        match expanded {
+            Ast::Statements(statements) => {
+                assert_eq!(statements.len(), 1);
+                match &statements[0] {
+                    Ast::Directive { name, args, .. } => {
+                        assert_eq!(name, "byte");
+                        assert!(matches!(args[0], Ast::Number8(0x05)));
+                    }
+                    other => panic!("unexpected node : {:?}", other),
+                }
+            }
+            other => panic!("unexpected node : {:?}", other),
+        }
+    }
+
+    #[test]
+    fn arity_mismatch_is_an_error() {
+        let ast = Ast::Statements(vec![
+            def(&["addr"], vec![]),
+            Ast::MacroCall {
+                name: String::from("m"),
+                args: vec![],
+            },
+        ]);
+        assert!(expand_macros(ast).is_err());
+    }
+
+    #[test]
+    fn local_labels_get_suffixed_per_expansion_to_avoid_collisions() {
+        let body = vec![
+            Ast::Label(String::from("loop")),
+            Ast::Instruction {
+                instruction: String::from("BNE"),
+                args: Some(Box::new(Ast::Label(String::from("loop")))),
+                span: Span::default(),
+            },
+        ];
+        let ast = Ast::Statements(vec![
+            def(&[], body),
+            Ast::MacroCall {
+                name: String::from("m"),
+                args: vec![],
+            },
+            Ast::MacroCall {
+                name: String::from("m"),
+                args: vec![],
+            },
+        ]);
+        let expanded = expand_macros(ast).unwrap();
+        match expanded {
+            Ast::Statements(statements) => {
+                let labels: Vec<&str> = statements
+                    .iter()
+                    .filter_map(|s| match s {
+                        Ast::Label(name) => Some(name.as_str()),
+                        _ => None,
+                    })
+                    .collect();
+                assert_eq!(labels.len(), 2);
+                assert_ne!(labels[0], labels[1]);
+            }
+            other => panic!("unexpected node : {:?}", other),
+        }
+    }
+}
@@ -1,56 +1,138 @@
 use super::ast::Ast;
-use super::lexer::{get_token_type, Lexer, Symbol, Token, TokenType};
+use super::lexer::{get_token_type, LexError, Lexer, Span, Spanned, Symbol, Token, TokenType};
+
+/// An error raised while parsing, carrying the span it was raised at so
+/// callers can print `line:col` plus the offending source slice.
+#[derive(Debug)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.span.start.line, self.span.start.col, self.message
+        )
+    }
+}
 
 pub struct Parser {
     lexer: Lexer,
-    current_token: Option<Token>,
+    current_token: Spanned<Token>,
+    macro_names: std::collections::HashSet<String>,
+    // Set when the very first token can't be lexed (e.g. the source starts
+    // with an invalid character). There's no `Result`-returning constructor
+    // in this codebase, so the error is stashed here and surfaced by
+    // `try_parse` instead of panicking in `new`.
+    init_error: Option<LexError>,
+    // Span of the last token `advance` consumed, so a construct spanning
+    // several tokens (a mnemonic plus an indexed operand like `$AA,X`) can
+    // report a span covering the whole thing via `Span::merge` instead of
+    // just its first token.
+    last_span: Span,
 }
 
 impl Parser {
     pub fn new(text: String) -> Self {
-        let mut parser = Self {
-            lexer: Lexer::new(text),
-            current_token: None,
+        let mut lexer = Lexer::new(text);
+        let (current_token, init_error) = match lexer.get_next_token() {
+            Ok(token) => (token, None),
+            Err(err) => (
+                Spanned {
+                    node: Token::Eof,
+                    span: err.span,
+                },
+                Some(err),
+            ),
         };
-        parser.current_token = Some(parser.lexer.get_next_token().unwrap());
-        parser
+        let last_span = current_token.span;
+        Self {
+            lexer,
+            current_token,
+            macro_names: std::collections::HashSet::new(),
+            init_error,
+            last_span,
+        }
     }
 
     //program = statements EOF
     pub fn parse(&mut self) -> Ast {
+        match self.try_parse() {
+            Ok(ast) => ast,
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    pub fn try_parse(&mut self) -> Result<Ast, ParseError> {
+        if let Some(err) = self.init_error.take() {
+            return Err(self.error_at(err.message, err.span));
+        }
         self.statements()
     }
 
     //statements = (statement NEW_LINE)+
     #[deny(clippy::while_immutable_condition)]
-    fn statements(&mut self) -> Ast {
+    fn statements(&mut self) -> Result<Ast, ParseError> {
         let mut statements = Vec::new();
         let token_type = self.get_current_token_type();
         while [TokenType::Text, TokenType::Symbol].contains(&token_type) {
-            let statement = self.statement();
+            let statement = self.statement()?;
             if let Some(statement) = statement {
                 statements.push(statement)
             }
             if self.current_token_is(&Token::Symbol(Symbol::NewLine)) {
-                self.eat(Token::Symbol(Symbol::NewLine));
+                self.eat(Token::Symbol(Symbol::NewLine))?;
             } else if self.current_token_is(&Token::Eof) {
-                self.eat(Token::Eof);
+                self.eat(Token::Eof)?;
                 break;
             }
         }
-        Ast::Statements(statements)
+        Ok(Ast::Statements(statements))
     }
 
-    //statement = comment | (LABEL COLON | INSTRUCTION (args)?) (comment)?
-    fn statement(&mut self) -> Option<Ast> {
+    //statement = comment | directive | macro_def | macro_call | const_def
+    //           | (LABEL COLON | INSTRUCTION (args)?) (comment)?
+    fn statement(&mut self) -> Result<Option<Ast>, ParseError> {
         if self.current_token_is(&Token::Symbol(Symbol::SemiColon)) {
-            self.comment();
-            return None;
+            self.comment()?;
+            return Ok(None);
+        }
+        if self.current_token_is(&Token::Text(String::from(".macro"))) {
+            return Ok(Some(self.macro_def()?));
+        }
+        if let Token::Text(t) = self.get_current_token() {
+            if t.starts_with('.') {
+                return Ok(Some(self.directive()?));
+            }
+        }
+        let token = self.eat_type(TokenType::Text)?;
+        if let Token::Text(name) = &token.node {
+            if self.macro_names.contains(name) {
+                return Ok(Some(self.macro_call(name.clone())?));
+            }
+        }
+        if self.current_token_is(&Token::Text(String::from("="))) {
+            self.eat(Token::Text(String::from("=")))?;
+            let value = self.macro_arg()?;
+            let name = match token.node {
+                Token::Text(t) => t,
+                other => unreachable!("eat_type(Text) returned {:?}", other),
+            };
+            if self.current_token_is(&Token::Symbol(Symbol::SemiColon)) {
+                self.comment()?;
+            }
+            return Ok(Some(Ast::ConstDef {
+                name,
+                value: Box::new(value),
+                span: token.span,
+            }));
         }
-        let token = self.eat_type(TokenType::Text);
         let (is_label, arg) = match self.get_current_token() {
             Token::Symbol(Symbol::Colon) => {
-                self.eat(Token::Symbol(Symbol::Colon));
+                self.eat(Token::Symbol(Symbol::Colon))?;
                 (true, None)
             }
             Token::Binary(_)
@@ -59,218 +141,408 @@ impl Parser {
             | Token::Hexa8(_)
             | Token::Symbol(Symbol::LPar)
             | Token::Symbol(Symbol::HashTag)
-            | Token::Text(_) => (false, Some(Box::new(self.arg()))),
+            | Token::Text(_) => (false, Some(Box::new(self.arg()?))),
             _ => (false, None),
         };
-        if let Token::Text(t) = token {
+        // With an operand, the instruction's span covers the mnemonic
+        // through the last token of the operand (e.g. the `X` of `$AA,X`),
+        // not just the mnemonic itself.
+        let span = match &arg {
+            Some(_) => token.span.merge(&self.last_span),
+            None => token.span,
+        };
+        if let Token::Text(t) = token.node {
             let ast = if is_label {
                 Ast::Label(t)
             } else {
                 Ast::Instruction {
                     instruction: t,
                     args: arg,
+                    span,
                 }
             };
             if self.current_token_is(&Token::Symbol(Symbol::SemiColon)) {
-                self.comment();
+                self.comment()?;
             }
-            Some(ast)
+            Ok(Some(ast))
         } else {
-            panic!("Unexpected token : {:?}", token)
+            Err(self.error_at(format!("Unexpected token : {:?}", token.node), token.span))
         }
     }
 
     //comment = SEMICOLON
-    fn comment(&mut self) {
-        self.eat(Token::Symbol(Symbol::SemiColon));
+    fn comment(&mut self) -> Result<(), ParseError> {
+        self.eat(Token::Symbol(Symbol::SemiColon))?;
+        Ok(())
+    }
+
+    //macro_def = '.macro' NAME (param (COMA param)*)? NEWLINE statements '.endmacro'
+    fn macro_def(&mut self) -> Result<Ast, ParseError> {
+        self.eat(Token::Text(String::from(".macro")))?;
+        let name = match self.eat_type(TokenType::Text)?.node {
+            Token::Text(t) => t,
+            other => unreachable!("eat_type(Text) returned {:?}", other),
+        };
+        // Register the name before parsing the body so the macro can call
+        // itself recursively.
+        self.macro_names.insert(name.clone());
+        let mut params = Vec::new();
+        if self.get_current_token_type() == TokenType::Text {
+            loop {
+                match self.eat_type(TokenType::Text)?.node {
+                    Token::Text(param) => params.push(param),
+                    other => unreachable!("eat_type(Text) returned {:?}", other),
+                }
+                if self.current_token_is(&Token::Symbol(Symbol::Coma)) {
+                    self.eat(Token::Symbol(Symbol::Coma))?;
+                } else {
+                    break;
+                }
+            }
+        }
+        self.eat(Token::Symbol(Symbol::NewLine))?;
+        let mut body = Vec::new();
+        while !self.current_token_is(&Token::Text(String::from(".endmacro"))) {
+            if self.current_token_is(&Token::Eof) {
+                return Err(
+                    self.error_here(format!("Unterminated macro `{name}` : missing .endmacro"))
+                );
+            }
+            if let Some(statement) = self.statement()? {
+                body.push(statement);
+            }
+            if self.current_token_is(&Token::Symbol(Symbol::NewLine)) {
+                self.eat(Token::Symbol(Symbol::NewLine))?;
+            }
+        }
+        self.eat(Token::Text(String::from(".endmacro")))?;
+        Ok(Ast::MacroDef { name, params, body })
+    }
+
+    //macro_call = NAME (macro_arg (COMA macro_arg)*)?
+    fn macro_call(&mut self, name: String) -> Result<Ast, ParseError> {
+        let mut args = Vec::new();
+        if self.arg_follows() {
+            loop {
+                args.push(self.macro_arg()?);
+                if self.current_token_is(&Token::Symbol(Symbol::Coma)) {
+                    self.eat(Token::Symbol(Symbol::Coma))?;
+                } else {
+                    break;
+                }
+            }
+        }
+        if self.current_token_is(&Token::Symbol(Symbol::SemiColon)) {
+            self.comment()?;
+        }
+        Ok(Ast::MacroCall { name, args })
+    }
+
+    // directive = ('.org' | '.res') macro_arg
+    //           | ('.byte' | '.db' | '.word' | '.dw') macro_arg (COMA macro_arg)*
+    fn directive(&mut self) -> Result<Ast, ParseError> {
+        let token = self.eat_type(TokenType::Text)?;
+        let (raw, span) = match token.node {
+            Token::Text(t) => (t, token.span),
+            other => unreachable!("eat_type(Text) returned {:?}", other),
+        };
+        let name = raw.trim_start_matches('.').to_lowercase();
+        let args = match name.as_str() {
+            "org" | "res" => vec![self.macro_arg()?],
+            "byte" | "db" | "word" | "dw" => {
+                let mut args = vec![self.macro_arg()?];
+                while self.current_token_is(&Token::Symbol(Symbol::Coma)) {
+                    self.eat(Token::Symbol(Symbol::Coma))?;
+                    args.push(self.macro_arg()?);
+                }
+                args
+            }
+            _ => return Err(self.error_at(format!("Unknown directive `{raw}`"), span)),
+        };
+        if self.current_token_is(&Token::Symbol(Symbol::SemiColon)) {
+            self.comment()?;
+        }
+        Ok(Ast::Directive { name, args, span })
+    }
+
+    fn arg_follows(&self) -> bool {
+        matches!(
+            self.get_current_token(),
+            Token::Binary(_)
+                | Token::Decimal(_)
+                | Token::Hexa16(_)
+                | Token::Hexa8(_)
+                | Token::Symbol(Symbol::LPar)
+                | Token::Symbol(Symbol::HashTag)
+                | Token::Text(_)
+        )
+    }
+
+    // macro_arg = label | immediate | hexa16 | hexa8 | decimal | binary
+    //
+    // Unlike an instruction's `arg`, a macro argument never carries an
+    // indexed-addressing suffix: a COMA after a macro argument always
+    // introduces the next argument, not a `,X`/`,Y` register index.
+    fn macro_arg(&mut self) -> Result<Ast, ParseError> {
+        match self.get_current_token_type() {
+            TokenType::Hexa16 => {
+                let token = self.eat_type(TokenType::Hexa16)?;
+                match token.node {
+                    Token::Hexa16(h) => Ok(Ast::Absolute(h)),
+                    other => unreachable!("eat_type(Hexa16) returned {:?}", other),
+                }
+            }
+            TokenType::Hexa8 => {
+                let token = self.eat_type(TokenType::Hexa8)?;
+                match token.node {
+                    Token::Hexa8(h) => Ok(Ast::ZeroPage(h)),
+                    other => unreachable!("eat_type(Hexa8) returned {:?}", other),
+                }
+            }
+            TokenType::Decimal | TokenType::Binary => self.number(),
+            TokenType::Symbol => match self.get_current_token() {
+                Token::Symbol(Symbol::HashTag) => self.immediate(),
+                _ => Err(self.error_here(format!(
+                    "Parser : unexpected token in macro argument, {:?}",
+                    self.current_token.node
+                ))),
+            },
+            TokenType::Text => self.label(),
+            _ => Err(self.error_here(String::from(
+                "Parser : unexpected token in macro argument",
+            ))),
+        }
     }
 
     //args = label| immediate | absolute | zero_page | indirect
-    fn arg(&mut self) -> Ast {
+    fn arg(&mut self) -> Result<Ast, ParseError> {
         match self.get_current_token_type() {
             TokenType::Hexa16 => self.absolute(),
             TokenType::Hexa8 => self.zero_page(),
             TokenType::Symbol => match self.get_current_token() {
                 Token::Symbol(Symbol::LPar) => self.indirect(),
                 Token::Symbol(Symbol::HashTag) => self.immediate(),
-                _ => panic!(
+                _ => Err(self.error_here(format!(
                     "Parser : unexpected token, {:?}",
-                    self.current_token.as_ref().unwrap()
-                ),
+                    self.current_token.node
+                ))),
             },
             TokenType::Text => self.label(),
-            _ => panic!("Parser : unexpected token"),
+            _ => Err(self.error_here(String::from("Parser : unexpected token"))),
         }
     }
 
     //immediate = HASHTAG number
-    fn immediate(&mut self) -> Ast {
-        self.eat(Token::Symbol(Symbol::HashTag));
+    fn immediate(&mut self) -> Result<Ast, ParseError> {
+        self.eat(Token::Symbol(Symbol::HashTag))?;
         self.number()
     }
 
-    //number = binary | hexa8 | hexa16 | decimal
-    fn number(&mut self) -> Ast {
+    //number = binary | hexa8 | decimal | label
+    fn number(&mut self) -> Result<Ast, ParseError> {
+        // A bare identifier here is a macro parameter used as an immediate
+        // operand (`LDA #value`). Wrap it in `Ast::Immediate` rather than
+        // handing back the `Ast::Label` as-is: the macro-expansion
+        // substitution pass will drop in whatever `Ast` the call argument
+        // parsed to (`Number8`, `ZeroPage`, a constant `Label`, ...), and
+        // without the wrapper codegen would pick an addressing mode off
+        // that substituted node instead of treating it as an immediate.
+        if self.get_current_token_type() == TokenType::Text {
+            return Ok(Ast::Immediate(Box::new(self.label()?)));
+        }
         let (token_type, ast) = match self.get_current_token() {
             Token::Decimal(d) => (TokenType::Decimal, Ast::Number8(*d)),
             Token::Binary(b) => (TokenType::Binary, Ast::Number8(*b)),
             Token::Hexa8(h) => (TokenType::Hexa8, Ast::Number8(*h)),
-            _ => panic!("Parser : number: unexpected token"),
+            _ => return Err(self.error_here(String::from("Parser : number: unexpected token"))),
         };
-        self.eat_type(token_type);
-        ast
+        self.eat_type(token_type)?;
+        Ok(ast)
     }
 
     //label = LABEL
-    fn label(&mut self) -> Ast {
-        let ast = if let Token::Text(t) = self.eat_type(TokenType::Text) {
-            if ["A", "a"].contains(&t.as_ref()){
-                Ast::Accumulator
+    fn label(&mut self) -> Result<Ast, ParseError> {
+        let token = self.eat_type(TokenType::Text)?;
+        if let Token::Text(t) = token.node {
+            if ["A", "a"].contains(&t.as_ref()) {
+                Ok(Ast::Accumulator)
             } else {
-                Ast::Label(t)
+                Ok(Ast::Label(t))
             }
         } else {
-            panic!("Unexpected token {:?}", self.current_token);
-        };
-        ast
+            Err(self.error_at(format!("Unexpected token {:?}", token.node), token.span))
+        }
     }
 
     // absolute = hexa16 (COMA [X,Y])?
-    fn absolute(&mut self) -> Ast {
-        let token = self.eat_type(TokenType::Hexa16);
-        if let Token::Hexa16(h) = token {
+    fn absolute(&mut self) -> Result<Ast, ParseError> {
+        let token = self.eat_type(TokenType::Hexa16)?;
+        if let Token::Hexa16(h) = token.node {
             if self.current_token_is(&Token::Symbol(Symbol::Coma)) {
-                self.eat(Token::Symbol(Symbol::Coma));
-                let token = self.eat_type(TokenType::Text);
-                if let Token::Text(t) = token {
+                self.eat(Token::Symbol(Symbol::Coma))?;
+                let reg_span = self.current_token.span;
+                let token = self.eat_type(TokenType::Text)?;
+                if let Token::Text(t) = token.node {
                     match t.as_ref() {
-                        "x" | "X" | "y" | "Y" => Ast::AsoluteIndexed(h, t.chars().next().unwrap()),
-                        _ => panic!("Unexpected token:  {:?}", t),
+                        "x" | "X" | "y" | "Y" => {
+                            Ok(Ast::AsoluteIndexed(h, t.chars().next().unwrap()))
+                        }
+                        _ => Err(self.error_at(format!("Unexpected token:  {:?}", t), reg_span)),
                     }
                 } else {
-                    panic!("Unexpected Token : {:?}", token)
+                    Err(self.error_at(format!("Unexpected Token : {:?}", token.node), token.span))
                 }
             } else {
-                Ast::Absolute(h)
+                Ok(Ast::Absolute(h))
             }
         } else {
-            panic!("Unexpected Token : {:?}", token);
+            Err(self.error_at(format!("Unexpected Token : {:?}", token.node), token.span))
         }
     }
 
     // zero_page = hexa8 (COMA [X,Y])?
-    fn zero_page(&mut self) -> Ast {
-        let token = self.eat_type(TokenType::Hexa8);
-        if let Token::Hexa8(h) = token {
+    fn zero_page(&mut self) -> Result<Ast, ParseError> {
+        let token = self.eat_type(TokenType::Hexa8)?;
+        if let Token::Hexa8(h) = token.node {
             if self.current_token_is(&Token::Symbol(Symbol::Coma)) {
-                self.eat(Token::Symbol(Symbol::Coma));
-                let token = self.eat_type(TokenType::Text);
-                if let Token::Text(t) = token {
+                self.eat(Token::Symbol(Symbol::Coma))?;
+                let reg_span = self.current_token.span;
+                let token = self.eat_type(TokenType::Text)?;
+                if let Token::Text(t) = token.node {
                     match t.as_ref() {
-                        "x" | "X" | "y" | "Y" => Ast::ZeroPageIndexed(h, t.chars().next().unwrap()),
-                        _ => panic!("Unexpected token:  {:?}", t),
+                        "x" | "X" | "y" | "Y" => {
+                            Ok(Ast::ZeroPageIndexed(h, t.chars().next().unwrap()))
+                        }
+                        _ => Err(self.error_at(format!("Unexpected token:  {:?}", t), reg_span)),
                     }
                 } else {
-                    panic!("Unexpected Token : {:?}", token)
+                    Err(self.error_at(format!("Unexpected Token : {:?}", token.node), token.span))
                 }
             } else {
-                Ast::ZeroPage(h)
+                Ok(Ast::ZeroPage(h))
             }
         } else {
-            panic!("Unexpected Token : {:?}", token);
+            Err(self.error_at(format!("Unexpected Token : {:?}", token.node), token.span))
         }
     }
 
     // indirect = LPAR (absolute_indirect | zero_page_indirect)
-    fn indirect(&mut self) -> Ast {
-        self.eat(Token::Symbol(Symbol::LPar));
+    fn indirect(&mut self) -> Result<Ast, ParseError> {
+        self.eat(Token::Symbol(Symbol::LPar))?;
         match self.get_current_token() {
             Token::Hexa16(_) => self.absolute_indirect(),
             Token::Hexa8(_) => self.zero_page_indirect(),
-            _ => panic!("Unexpected token : {:?}", self.current_token.as_ref()),
+            _ => Err(self.error_here(format!(
+                "Unexpected token : {:?}",
+                self.current_token.node
+            ))),
         }
     }
 
     // absolute_indirect = hexa16 RPAR
-    fn absolute_indirect(&mut self) -> Ast {
-        let token = self.eat_type(TokenType::Hexa16);
-        if let Token::Hexa16(h) = token {
-            self.eat(Token::Symbol(Symbol::RPar));
-            Ast::AbsoluteIndirect(h)
+    fn absolute_indirect(&mut self) -> Result<Ast, ParseError> {
+        let token = self.eat_type(TokenType::Hexa16)?;
+        if let Token::Hexa16(h) = token.node {
+            self.eat(Token::Symbol(Symbol::RPar))?;
+            Ok(Ast::AbsoluteIndirect(h))
         } else {
-            panic!("Unexpected token : {:?}", token)
+            Err(self.error_at(format!("Unexpected token : {:?}", token.node), token.span))
         }
     }
 
     // zero_page_indirect = hexa8 COMA X RPAR | hexa8 RPAR COMA Y
-    fn zero_page_indirect(&mut self) -> Ast {
-        let token = self.eat_type(TokenType::Hexa8);
-        if let Token::Hexa8(h) = token {
-            let token = self.eat_type(TokenType::Symbol);
-            match token {
+    fn zero_page_indirect(&mut self) -> Result<Ast, ParseError> {
+        let token = self.eat_type(TokenType::Hexa8)?;
+        let span = token.span;
+        if let Token::Hexa8(h) = token.node {
+            let token = self.eat_type(TokenType::Symbol)?;
+            match token.node {
                 Token::Symbol(Symbol::RPar) => {
-                    self.eat(Token::Symbol(Symbol::Coma));
-                    let token = self.eat_type(TokenType::Text);
-                    if let Token::Text(t) = token {
+                    self.eat(Token::Symbol(Symbol::Coma))?;
+                    let reg_span = self.current_token.span;
+                    let token = self.eat_type(TokenType::Text)?;
+                    if let Token::Text(t) = token.node {
                         if t.to_uppercase() != "Y" {
-                            panic!("Unexpected token : {:?}", t);
+                            return Err(self.error_at(format!("Unexpected token : {:?}", t), reg_span));
                         }
                     }
-                    Ast::IndirectY(h)
+                    Ok(Ast::IndirectY(h))
                 }
                 Token::Symbol(Symbol::Coma) => {
-                    let token = self.eat_type(TokenType::Text);
-                    if let Token::Text(t) = token {
+                    let reg_span = self.current_token.span;
+                    let token = self.eat_type(TokenType::Text)?;
+                    if let Token::Text(t) = token.node {
                         if t.to_uppercase() != "X" {
-                            panic!("Unexpected token : {:?}", t);
+                            return Err(self.error_at(format!("Unexpected token : {:?}", t), reg_span));
                         }
                     }
-                    self.eat(Token::Symbol(Symbol::RPar));
-                    Ast::IndirectX(h)
+                    self.eat(Token::Symbol(Symbol::RPar))?;
+                    Ok(Ast::IndirectX(h))
                 }
-                _ => panic!("Unexpected token : {:?}", token),
+                _ => Err(self.error_at(format!("Unexpected token : {:?}", token.node), token.span)),
             }
         } else {
-            panic!("Unexpected token : {:?}", token)
+            Err(self.error_at(format!("Unexpected token : {:?}", token.node), span))
         }
     }
 
-    fn eat(&mut self, token: Token) -> Token {
-        let current = self.current_token.take();
-        if *current.as_ref().unwrap() == token {
-            self.current_token = Some(self.lexer.get_next_token().unwrap());
-            current.unwrap()
+    fn eat(&mut self, token: Token) -> Result<Spanned<Token>, ParseError> {
+        if self.current_token.node == token {
+            self.advance()
         } else {
-            panic!(
+            Err(self.error_here(format!(
                 "Unexpected token : {:?}, should be {:?}",
-                self.current_token, token
-            )
+                self.current_token.node, token
+            )))
         }
     }
 
-    fn eat_type(&mut self, token_type: TokenType) -> Token {
-        let current = self.current_token.take();
-        if get_token_type(current.as_ref().unwrap()) == token_type {
-            self.current_token = Some(self.lexer.get_next_token().unwrap());
-            current.unwrap()
+    fn eat_type(&mut self, token_type: TokenType) -> Result<Spanned<Token>, ParseError> {
+        if get_token_type(&self.current_token.node) == token_type {
+            self.advance()
         } else {
-            panic!(
+            Err(self.error_here(format!(
                 "Unexpected token : {:?}, should be {:?}",
-                self.current_token, token_type
-            )
+                self.current_token.node, token_type
+            )))
         }
     }
 
+    fn advance(&mut self) -> Result<Spanned<Token>, ParseError> {
+        let next = self.lexer.get_next_token().map_err(|err| {
+            let source = self.lexer.slice(&err.span);
+            ParseError {
+                message: format!("{} (near `{source}`)", err.message),
+                span: err.span,
+            }
+        })?;
+        let prev = std::mem::replace(&mut self.current_token, next);
+        self.last_span = prev.span;
+        Ok(prev)
+    }
+
     fn current_token_is(&self, token: &Token) -> bool {
-        *self.current_token.as_ref().unwrap() == *token
+        self.current_token.node == *token
     }
 
     fn get_current_token_type(&self) -> TokenType {
-        get_token_type(self.current_token.as_ref().unwrap())
+        get_token_type(&self.current_token.node)
     }
 
     fn get_current_token(&self) -> &Token {
-        self.current_token.as_ref().unwrap()
+        &self.current_token.node
+    }
+
+    fn error_here(&self, message: String) -> ParseError {
+        self.error_at(message, self.current_token.span)
+    }
+
+    fn error_at(&self, message: String, span: Span) -> ParseError {
+        let source = self.lexer.slice(&span);
+        ParseError {
+            message: format!("{message} (near `{source}`)"),
+            span,
+        }
     }
 }
 
@@ -291,4 +563,165 @@ mod tests {
         let mut parser = Parser::new(String::from(txt));
         parser.parse();
     }
+
+    #[test]
+    fn instruction_span_covers_the_whole_indexed_operand() {
+        let txt = String::from("ORA $F4F5,X");
+        let mut parser = Parser::new(txt.clone());
+        match parser.parse() {
+            Ast::Statements(statements) => match &statements[0] {
+                Ast::Instruction { span, .. } => {
+                    assert_eq!(&txt[span.start_offset..span.end_offset], "ORA $F4F5,X");
+                }
+                other => panic!("expected instruction, got {:?}", other),
+            },
+            other => panic!("expected statements, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_span_on_unknown_char() {
+        let txt = String::from("STA $AA\n@");
+        let mut parser = Parser::new(txt);
+        let err = parser.try_parse().unwrap_err();
+        assert_eq!(err.span.start.line, 2);
+        assert_eq!(err.span.start.col, 1);
+    }
+
+    #[test]
+    fn reports_span_when_the_first_char_is_unknown() {
+        // An unlexable first character used to panic in `Parser::new`
+        // itself; it must now surface as an ordinary `try_parse` error.
+        let txt = String::from("@STA $AA");
+        let mut parser = Parser::new(txt);
+        let err = parser.try_parse().unwrap_err();
+        assert_eq!(err.span.start.line, 1);
+        assert_eq!(err.span.start.col, 1);
+    }
+
+    #[test]
+    fn parse_macro_def_and_call() {
+        let txt = String::from(
+            "        .macro poke addr, value\n                LDA value\n                STA addr\n        .endmacro\n        poke $10,#$20\n",
+        );
+        let mut parser = Parser::new(txt);
+        let ast = parser.try_parse().unwrap();
+        match ast {
+            Ast::Statements(statements) => {
+                assert_eq!(statements.len(), 2);
+                assert!(matches!(statements[0], Ast::MacroDef { .. }));
+                assert!(matches!(statements[1], Ast::MacroCall { .. }));
+            }
+            other => panic!("expected statements, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_macro_param_used_as_immediate_operand() {
+        let txt = String::from(
+            "        .macro loadimm value\n                LDA #value\n        .endmacro\n        loadimm $20\n",
+        );
+        let mut parser = Parser::new(txt);
+        let ast = parser.try_parse().unwrap();
+        match ast {
+            Ast::Statements(statements) => {
+                assert_eq!(statements.len(), 2);
+                match &statements[0] {
+                    Ast::MacroDef { body, .. } => {
+                        assert!(matches!(
+                            &body[0],
+                            Ast::Instruction { args: Some(a), .. }
+                                if matches!(
+                                    a.as_ref(),
+                                    Ast::Immediate(inner) if matches!(inner.as_ref(), Ast::Label(name) if name == "value")
+                                )
+                        ));
+                    }
+                    other => panic!("expected macro def, got {:?}", other),
+                }
+            }
+            other => panic!("expected statements, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn macro_call_arity_mismatch_is_a_parse_time_success_but_expansion_error() {
+        // Arity is only checked once the macro is expanded; parsing a call
+        // with the wrong number of arguments must still succeed here.
+        let txt = String::from(
+            "        .macro poke addr, value\n                LDA value\n        .endmacro\n        poke $10\n",
+        );
+        let mut parser = Parser::new(txt);
+        let ast = parser.try_parse();
+        assert!(ast.is_ok());
+        assert!(super::super::macros::expand_macros(ast.unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_directives() {
+        let txt = String::from(".org $0600\n.byte $01,$02\n.word $1234\n.res 4\n");
+        let mut parser = Parser::new(txt);
+        let ast = parser.try_parse().unwrap();
+        match ast {
+            Ast::Statements(statements) => {
+                assert_eq!(statements.len(), 4);
+                match &statements[0] {
+                    Ast::Directive { name, args, .. } => {
+                        assert_eq!(name, "org");
+                        assert!(matches!(args[0], Ast::Absolute(0x0600)));
+                    }
+                    other => panic!("expected directive, got {:?}", other),
+                }
+                match &statements[1] {
+                    Ast::Directive { name, args, .. } => {
+                        assert_eq!(name, "byte");
+                        assert_eq!(args.len(), 2);
+                    }
+                    other => panic!("expected directive, got {:?}", other),
+                }
+                match &statements[2] {
+                    Ast::Directive { name, args, .. } => {
+                        assert_eq!(name, "word");
+                        assert!(matches!(args[0], Ast::Absolute(0x1234)));
+                    }
+                    other => panic!("expected directive, got {:?}", other),
+                }
+                match &statements[3] {
+                    Ast::Directive { name, args, .. } => {
+                        assert_eq!(name, "res");
+                        assert_eq!(args.len(), 1);
+                    }
+                    other => panic!("expected directive, got {:?}", other),
+                }
+            }
+            other => panic!("expected statements, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_const_def() {
+        let txt = String::from("SCREEN = $0200\n");
+        let mut parser = Parser::new(txt);
+        let ast = parser.try_parse().unwrap();
+        match ast {
+            Ast::Statements(statements) => {
+                assert_eq!(statements.len(), 1);
+                match &statements[0] {
+                    Ast::ConstDef { name, value, .. } => {
+                        assert_eq!(name, "SCREEN");
+                        assert!(matches!(value.as_ref(), Ast::Absolute(0x0200)));
+                    }
+                    other => panic!("expected const def, got {:?}", other),
+                }
+            }
+            other => panic!("expected statements, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_directive_is_an_error() {
+        let txt = String::from(".nope $10\n");
+        let mut parser = Parser::new(txt);
+        assert!(parser.try_parse().is_err());
+    }
 }